@@ -0,0 +1,55 @@
+//! Background music: a self-contained plugin that plays a looping track on startup and
+//! toggles pause/resume on a keypress. Deliberately decoupled from the player systems — it
+//! only reads `Settings::master_volume`, nothing else in the game depends on it.
+
+use bevy::prelude::*;
+
+use crate::Settings;
+
+const TRACK_PATH: &str = "audio/background.ogg";
+const TOGGLE_KEY: KeyCode = KeyCode::M;
+
+pub struct AudioPlugin;
+
+impl Plugin for AudioPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_startup_system(play_music).add_system(toggle_music);
+    }
+}
+
+#[derive(Resource)]
+struct MusicSink(Handle<AudioSink>);
+
+fn play_music(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    audio: Res<Audio>,
+    settings: Res<Settings>,
+) {
+    let track = asset_server.load(TRACK_PATH);
+    let handle = audio.play_with_settings(
+        track,
+        PlaybackSettings::LOOP.with_volume(settings.master_volume),
+    );
+    commands.insert_resource(MusicSink(handle));
+}
+
+fn toggle_music(
+    keys: Res<Input<KeyCode>>,
+    settings: Res<Settings>,
+    music: Res<MusicSink>,
+    sinks: Res<Assets<AudioSink>>,
+) {
+    let Some(sink) = sinks.get(&music.0) else {
+        return;
+    };
+
+    if keys.just_pressed(TOGGLE_KEY) {
+        if sink.is_paused() {
+            sink.play();
+        } else {
+            sink.pause();
+        }
+    }
+    sink.set_volume(settings.master_volume);
+}
@@ -1,61 +1,216 @@
+mod audio;
+mod net;
+
+use std::collections::HashMap;
+use std::f32::consts::FRAC_PI_2;
+
 use bevy::{
     input::mouse::MouseMotion,
     prelude::*,
     window::{CursorGrabMode, PrimaryWindow},
 };
 use bevy_aabb_instancing::VertexPullingRenderPlugin;
+use bevy_ggrs::{GgrsApp, GgrsPlugin, GgrsSchedule, LocalInputs, LocalPlayers, PlayerInputs, Session};
 use bevy_rapier3d::prelude::*;
+use clap::Parser;
+
+use net::{start_p2p_session, GgrsConfig, NetOpts, PlayerInput};
 
-const TAU: f32 = 6.283185307179586476925286766559;
 const GRAVITY: f32 = 3.;
 const PLAYER_WIDTH: f32 = 0.2;
 const PLAYER_HEIGHT: f32 = 1.6;
+const FPS: usize = 60;
+const DEFAULT_CUBEMAP_PATH: &str = "skybox/skybox.png";
+// The fixed step GGRS resimulates with. Every system in `GgrsSchedule` (Rapier's physics step
+// included) must advance by exactly this much instead of `Time`'s delta, since `GgrsSchedule`
+// can run several times in a single real frame during rollback and `Time` only advances once.
+const DT: f32 = 1.0 / FPS as f32;
 
 fn main() {
+    let net_opts = NetOpts::parse();
+    let local_handle = usize::from(!net_opts.host);
+    let session = start_p2p_session(&net_opts);
+
     App::new()
         .add_plugins(DefaultPlugins)
-        .add_plugin(RapierPhysicsPlugin::<NoUserData>::default())
+        // `with_default_system_setup(false)` stops Rapier wiring its systems into `PostUpdate`
+        // itself; we re-register its `PhysicsSet` stages into `GgrsSchedule` below instead, so a
+        // rollback resimulation re-steps physics exactly once per resimulated frame, same as
+        // `controller_update`/`update_grounded` — otherwise `KinematicCharacterControllerOutput`
+        // would still reflect last real frame's physics for every substep but the last.
+        .add_plugin(RapierPhysicsPlugin::<NoUserData>::default().with_default_system_setup(false))
         .add_plugin(RapierDebugRenderPlugin::default())
         .add_plugin(VertexPullingRenderPlugin::default())
+        .add_plugin(GgrsPlugin::<GgrsConfig>::default())
+        .add_plugin(audio::AudioPlugin)
         .init_resource::<Settings>()
+        .insert_resource(LocalHandle(local_handle))
+        .insert_resource(Session::P2PSession(session))
+        .insert_resource(RapierConfiguration {
+            timestep_mode: TimestepMode::Fixed {
+                dt: DT,
+                substeps: 1,
+            },
+            ..default()
+        })
+        .set_rollback_schedule_fps(FPS)
+        .rollback_component_with_copy::<Transform>()
+        .rollback_component_with_copy::<Player>()
+        .configure_sets(
+            (
+                PhysicsSet::SyncBackend,
+                PhysicsSet::StepSimulation,
+                PhysicsSet::Writeback,
+            )
+                .chain()
+                .in_schedule(GgrsSchedule),
+        )
+        .add_systems(
+            RapierPhysicsPlugin::<NoUserData>::get_systems(PhysicsSet::SyncBackend)
+                .in_set(PhysicsSet::SyncBackend)
+                .in_schedule(GgrsSchedule),
+        )
+        .add_systems(
+            RapierPhysicsPlugin::<NoUserData>::get_systems(PhysicsSet::StepSimulation)
+                .in_set(PhysicsSet::StepSimulation)
+                .in_schedule(GgrsSchedule),
+        )
+        .add_systems(
+            RapierPhysicsPlugin::<NoUserData>::get_systems(PhysicsSet::Writeback)
+                .in_set(PhysicsSet::Writeback)
+                .in_schedule(GgrsSchedule),
+        )
         .add_startup_system(setup)
         .add_system(grab_cursor)
         .add_system(camera_movement)
         .add_system(camera_follow)
-        .add_system(controller_update)
-        .add_system(movement)
-        .add_system(friction)
-        .add_system(gravity)
+        .add_system(read_local_inputs)
+        .add_system(weapon_sway_bob)
+        // `controller_update` has to land before Rapier's own `SyncBackend` systems consume
+        // `controller.translation`, and `update_grounded` has to come after `Writeback` has
+        // copied the resulting `KinematicCharacterControllerOutput` back onto the entity — a bare
+        // `.chain()` only orders these systems relative to each other, not to Rapier's.
+        .add_systems(
+            (movement, controller_update, gravity, friction)
+                .chain()
+                .before(PhysicsSet::SyncBackend)
+                .in_schedule(GgrsSchedule),
+        )
+        .add_systems(update_grounded.after(PhysicsSet::Writeback).in_schedule(GgrsSchedule))
         .add_system(debug_log)
         .run();
 }
 
+#[derive(Resource)]
+struct LocalHandle(usize);
+
 #[derive(Component)]
 struct Camera;
 
 #[derive(Component)]
+struct CameraController {
+    pitch: f32,
+    sensitivity: f32,
+    key_forward: KeyCode,
+    key_back: KeyCode,
+    key_left: KeyCode,
+    key_right: KeyCode,
+    key_jump: KeyCode,
+}
+
+impl Default for CameraController {
+    fn default() -> Self {
+        CameraController {
+            pitch: 0.0,
+            sensitivity: 0.001,
+            key_forward: KeyCode::E,
+            key_back: KeyCode::D,
+            key_left: KeyCode::S,
+            key_right: KeyCode::F,
+            key_jump: KeyCode::Space,
+        }
+    }
+}
+
+// Rollback-tracked: GGRS snapshots and restores this verbatim on resimulation, so every field
+// must be derived purely from `Transform` + `PlayerInputs` within the GGRS schedule.
+#[derive(Component, Clone, Copy)]
 struct Player {
+    handle: usize,
     is_grounded: bool,
     velocity: Vec3,
+    just_jumped: bool,
 }
 
 #[derive(Resource)]
 struct Settings {
-    sensitivity: f32,
+    ground_accel: f32,
+    air_accel: f32,
+    air_wishspeed: f32,
+    sway_amount: f32,
+    bob_amount: f32,
+    sway_return_stiffness: f32,
+    master_volume: f32,
+    /// Asset-relative path to the skybox panorama (an equirectangular JPG/PNG, not a cubemap);
+    /// swap it to change the environment without a rebuild.
+    cubemap_path: String,
 }
 
 impl Default for Settings {
     fn default() -> Self {
-        Settings { sensitivity: 0.001 }
+        Settings {
+            ground_accel: 14.0,
+            air_accel: 2.0,
+            air_wishspeed: 0.5,
+            sway_amount: 0.01,
+            bob_amount: 0.02,
+            sway_return_stiffness: 8.0,
+            master_volume: 0.5,
+            cubemap_path: DEFAULT_CUBEMAP_PATH.to_string(),
+        }
     }
 }
 
+const WEAPON_REST_POS: Vec3 = Vec3::new(0.3, -0.3, -0.5);
+const WEAPON_MAX_SWAY: f32 = 0.15;
+
+// Procedural first-person viewmodel state: `sway_*` leans away from mouse motion and springs
+// back to rest, `bob_distance` accumulates the player's horizontal travel to drive a walk bob.
+#[derive(Component, Default)]
+struct Weapon {
+    sway_yaw: f32,
+    sway_pitch: f32,
+    bob_distance: f32,
+}
+
 pub fn setup(
     mut commands: Commands,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
     mut primary_window: Query<&mut Window, With<PrimaryWindow>>,
+    asset_server: Res<AssetServer>,
+    settings: Res<Settings>,
 ) {
+    // A large inverted sphere wrapped in an unlit equirectangular panorama, rather than
+    // `bevy_core_pipeline`'s `Skybox` component — that's 0.11+ only, and the rest of this file
+    // targets the 0.10 API (`add_system`, single-arg `add_systems`).
+    commands.spawn(PbrBundle {
+        mesh: meshes.add(
+            shape::UVSphere {
+                radius: 500.0,
+                ..default()
+            }
+            .into(),
+        ),
+        material: materials.add(StandardMaterial {
+            base_color_texture: Some(asset_server.load(&settings.cubemap_path)),
+            unlit: true,
+            cull_mode: None,
+            ..default()
+        }),
+        ..default()
+    });
+
     // Floor
     commands.spawn((
         PbrBundle {
@@ -86,25 +241,58 @@ pub fn setup(
         ..default()
     });
 
-    // Player
-    commands.spawn((
-        TransformBundle::default(),
-        RigidBody::KinematicVelocityBased,
-        KinematicCharacterController { ..default() },
-        Collider::cuboid(PLAYER_WIDTH, PLAYER_HEIGHT, PLAYER_WIDTH),
-        Player {
-            is_grounded: true,
-            velocity: Vec3::ZERO,
-        },
-    ));
-    // Camera
-    commands.spawn((
-        Camera3dBundle {
-            transform: Transform::from_xyz(0.0, PLAYER_HEIGHT, 0.0),
-            ..default()
-        },
-        Camera,
-    ));
+    // One player/collider per GGRS handle, spread out so they don't spawn inside each other.
+    for handle in 0..2 {
+        commands.spawn((
+            TransformBundle::from_transform(Transform::from_xyz(handle as f32 * 2.0, 0.0, 0.0)),
+            RigidBody::KinematicVelocityBased,
+            KinematicCharacterController {
+                up: Vec3::Y,
+                max_slope_climb_angle: 45f32.to_radians(),
+                min_slope_slide_angle: 30f32.to_radians(),
+                autostep: Some(CharacterAutostep {
+                    max_height: CharacterLength::Absolute(0.3),
+                    min_width: CharacterLength::Absolute(0.1),
+                    include_dynamic_bodies: true,
+                }),
+                snap_to_ground: Some(CharacterLength::Absolute(0.1)),
+                ..default()
+            },
+            Collider::cuboid(PLAYER_WIDTH, PLAYER_HEIGHT, PLAYER_WIDTH),
+            Player {
+                handle,
+                is_grounded: true,
+                velocity: Vec3::ZERO,
+                just_jumped: false,
+            },
+        ));
+    }
+    // Camera (follows this process's local player only; see `camera_follow`)
+    let camera = commands
+        .spawn((
+            Camera3dBundle {
+                transform: Transform::from_xyz(0.0, PLAYER_HEIGHT, 0.0),
+                ..default()
+            },
+            Camera,
+            CameraController::default(),
+        ))
+        .id();
+
+    // Weapon viewmodel, held as a child of the camera so it moves and rotates with the view
+    // before `weapon_sway_bob` layers procedural offsets on top each frame.
+    let weapon = commands
+        .spawn((
+            PbrBundle {
+                mesh: meshes.add(shape::Box::new(0.1, 0.1, 0.4).into()),
+                material: materials.add(Color::rgb(0.2, 0.2, 0.2).into()),
+                transform: Transform::from_translation(WEAPON_REST_POS),
+                ..default()
+            },
+            Weapon::default(),
+        ))
+        .id();
+    commands.entity(camera).add_child(weapon);
 
     if let Ok(mut window) = primary_window.get_single_mut() {
         window.cursor.grab_mode = CursorGrabMode::Confined;
@@ -129,103 +317,191 @@ fn grab_cursor(
 }
 
 fn camera_follow(
-    mut camera_transform: Query<&mut Transform, With<Camera>>,
-    player_transform: Query<&GlobalTransform, With<Player>>,
+    local_handle: Res<LocalHandle>,
+    mut camera: Query<(&mut Transform, &CameraController), With<Camera>>,
+    player: Query<(&Transform, &Player), Without<Camera>>,
 ) {
-    let mut camera_transform = camera_transform
-        .get_single_mut()
-        .expect("Camera has transform");
-    let player_transform = player_transform.get_single().expect("Player has transform");
-    camera_transform.translation = player_transform.translation() + Vec3::Y * PLAYER_HEIGHT;
+    let (mut camera_transform, controller) =
+        camera.get_single_mut().expect("Camera has transform");
+    let Some((player_transform, _)) = player.iter().find(|(_, p)| p.handle == local_handle.0)
+    else {
+        return;
+    };
+    camera_transform.translation = player_transform.translation + Vec3::Y * PLAYER_HEIGHT;
+    camera_transform.rotation =
+        player_transform.rotation * Quat::from_axis_angle(Vec3::X, controller.pitch);
 }
 
+// Pitch is local-only (it never affects movement direction, so it doesn't need to be rolled
+// back); yaw lives on the rollback-tracked `Player` transform and is advanced deterministically
+// from networked input in `movement` instead.
 fn camera_movement(
     mut mouse_motion_events: EventReader<MouseMotion>,
-    mut camera: Query<&mut Transform, With<Camera>>,
+    mut camera: Query<&mut CameraController, With<Camera>>,
     primary_window: Query<&Window, With<PrimaryWindow>>,
-    settings: Res<Settings>,
 ) {
     if let Ok(win) = primary_window.get_single() {
         for MouseMotion { delta } in mouse_motion_events.iter() {
-            for mut transform in camera.iter_mut() {
-                let (mut yaw, mut pitch, _) = transform.rotation.to_euler(EulerRot::YXZ);
-                match win.cursor.grab_mode {
-                    CursorGrabMode::None => (),
-                    _ => {
-                        yaw -= delta.x * settings.sensitivity;
-                        pitch -= delta.y * settings.sensitivity;
-                    }
+            for mut controller in camera.iter_mut() {
+                if !matches!(win.cursor.grab_mode, CursorGrabMode::None) {
+                    controller.pitch -= delta.y * controller.sensitivity;
                 }
-
-                // TODO: Fix bug where looking all the way down causes an error due to normalizing
-                // a zero vector.
-                // pitch = pitch.clamp(-TAU / 4.0, TAU / 4.0);
-                pitch = pitch.clamp(-TAU / 5.0, TAU / 5.0);
-
-                transform.rotation =
-                    Quat::from_axis_angle(Vec3::Y, yaw) * Quat::from_axis_angle(Vec3::X, pitch);
+                controller.pitch = controller
+                    .pitch
+                    .clamp(-FRAC_PI_2 + 0.001, FRAC_PI_2 - 0.001);
             }
         }
     }
 }
 
-fn controller_update(
+// Captures this process's local input once per render frame and hands it to GGRS, which
+// replays it deterministically (and resimulates past frames on misprediction) inside
+// `GgrsSchedule`.
+fn read_local_inputs(
+    mut commands: Commands,
+    keys: Res<Input<KeyCode>>,
+    mut mouse_motion_events: EventReader<MouseMotion>,
+    camera: Query<&CameraController, With<Camera>>,
+    local_players: Res<LocalPlayers>,
+) {
+    let controller = camera.get_single().expect("Camera exists");
+    let mouse_delta: Vec2 = mouse_motion_events.iter().map(|motion| motion.delta).sum();
+
+    let input = PlayerInput {
+        buttons: PlayerInput::pack(
+            keys.pressed(controller.key_forward),
+            keys.pressed(controller.key_back),
+            keys.pressed(controller.key_left),
+            keys.pressed(controller.key_right),
+            keys.pressed(controller.key_jump),
+        ),
+        yaw_delta: (-mouse_delta.x * controller.sensitivity * 1000.0) as i16,
+    };
+
+    let mut local_inputs = HashMap::new();
+    for handle in &local_players.0 {
+        local_inputs.insert(*handle, input);
+    }
+    commands.insert_resource(LocalInputs::<GgrsConfig>(local_inputs));
+}
+
+fn weapon_sway_bob(
     time: Res<Time>,
-    mut player: Query<(&mut KinematicCharacterController, &Player)>,
+    mut mouse_motion_events: EventReader<MouseMotion>,
+    settings: Res<Settings>,
+    local_handle: Res<LocalHandle>,
+    player: Query<&Player>,
+    mut weapon: Query<(&mut Transform, &mut Weapon)>,
 ) {
-    let (mut controller, player) = player.get_single_mut().expect("Player exists");
-    controller.translation = Some(player.velocity * time.delta_seconds());
+    let (mut transform, mut weapon) = weapon.get_single_mut().expect("Weapon exists");
+    let dt = time.delta_seconds();
+
+    let mouse_delta: Vec2 = mouse_motion_events.iter().map(|motion| motion.delta).sum();
+    weapon.sway_yaw = (weapon.sway_yaw - mouse_delta.x * settings.sway_amount)
+        .clamp(-WEAPON_MAX_SWAY, WEAPON_MAX_SWAY);
+    weapon.sway_pitch = (weapon.sway_pitch - mouse_delta.y * settings.sway_amount)
+        .clamp(-WEAPON_MAX_SWAY, WEAPON_MAX_SWAY);
+    // Spring back toward rest every frame, independent of whether the mouse moved this frame.
+    let spring = (settings.sway_return_stiffness * dt).min(1.0);
+    weapon.sway_yaw -= weapon.sway_yaw * spring;
+    weapon.sway_pitch -= weapon.sway_pitch * spring;
+
+    let speed = player
+        .iter()
+        .find(|p| p.handle == local_handle.0)
+        .map(|p| p.velocity.with_y(0.0).length())
+        .unwrap_or(0.0);
+    weapon.bob_distance += speed * dt;
+    let vertical_bob = weapon.bob_distance.sin() * settings.bob_amount;
+    let lateral_bob = (weapon.bob_distance * 0.5 + FRAC_PI_2).sin() * settings.bob_amount * 0.5;
+
+    transform.translation = WEAPON_REST_POS + Vec3::new(lateral_bob, vertical_bob, 0.0);
+    transform.rotation = Quat::from_euler(EulerRot::YXZ, weapon.sway_yaw, weapon.sway_pitch, 0.0);
+}
+
+fn controller_update(mut player: Query<(&mut KinematicCharacterController, &Player)>) {
+    for (mut controller, player) in player.iter_mut() {
+        controller.translation = Some(player.velocity * DT);
+    }
 }
 
 fn movement(
-    keys: Res<Input<KeyCode>>,
-    camera_transform: Query<&Transform, With<Camera>>,
-    mut player: Query<&mut Player>,
+    inputs: Res<PlayerInputs<GgrsConfig>>,
+    settings: Res<Settings>,
+    mut player: Query<(&mut Transform, &mut Player)>,
 ) {
-    let mut player = player.get_single_mut().expect("Player exists");
-    let camera_transform = camera_transform.get_single().expect("Camera exists");
-
-    let forward = {
-        let mut v = camera_transform.forward();
-        v.y = 0.0;
-        v.normalize()
-    };
-    let right = Vec3::new(-forward.z, 0.0, forward.x);
-    let speed = 1.0;
     let jump_speed = 2.0;
 
-    for &key in keys.get_pressed() {
-        match key {
-            KeyCode::E => player.velocity += forward * speed,
-            KeyCode::S => player.velocity -= right * speed,
-            KeyCode::D => player.velocity -= forward * speed,
-            KeyCode::F => player.velocity += right * speed,
-            KeyCode::Space if player.is_grounded => {
-                player.velocity.y += jump_speed;
-                player.is_grounded = false;
-            }
-            _ => {}
+    for (mut transform, mut player) in player.iter_mut() {
+        let (input, _) = inputs[player.handle];
+        player.just_jumped = false;
+
+        transform.rotate_y(input.yaw_delta as f32 / 1000.0);
+
+        let forward = transform.forward();
+        let right = Vec3::new(-forward.z, 0.0, forward.x);
+
+        let mut wishdir = Vec3::ZERO;
+        if input.forward() {
+            wishdir += forward;
+        }
+        if input.back() {
+            wishdir -= forward;
+        }
+        if input.left() {
+            wishdir -= right;
+        }
+        if input.right() {
+            wishdir += right;
+        }
+        if input.jump() && player.is_grounded {
+            player.velocity.y += jump_speed;
+            player.is_grounded = false;
+            player.just_jumped = true;
+        }
+        let wishdir = wishdir.normalize_or_zero();
+
+        let (accel, wishspeed) = if player.is_grounded {
+            (settings.ground_accel, 1.0)
+        } else {
+            (settings.air_accel, settings.air_wishspeed)
+        };
+
+        let current = player.velocity.dot(wishdir);
+        let add = wishspeed - current;
+        if add > 0.0 {
+            let accel_speed = (accel * DT * wishspeed).min(add);
+            player.velocity += wishdir * accel_speed;
         }
     }
 }
 
-fn gravity(time: Res<Time>, mut player: Query<(&mut Transform, &mut KinematicCharacterControllerOutput, &mut Player)>) {
-    let (mut transform, mut controller, mut player) = player.get_single_mut().expect("Player exists");
-    if !player.is_grounded {
-        player.velocity.y -= GRAVITY * time.delta_seconds();
+fn gravity(mut player: Query<&mut Player>) {
+    for mut player in player.iter_mut() {
+        if !player.is_grounded {
+            player.velocity.y -= GRAVITY * DT;
+        }
     }
-    if transform.translation.y < 0.0 {
-        transform.translation.y = 0.0;
-        player.velocity.y = 0.0;
-        player.is_grounded = true;
+}
+
+// Rapier only attaches `KinematicCharacterControllerOutput` once the controller has moved at
+// least once, so this runs after `controller_update` and tolerates it being absent on frame 1.
+fn update_grounded(mut player: Query<(Option<&KinematicCharacterControllerOutput>, &mut Player)>) {
+    for (output, mut player) in player.iter_mut() {
+        let Some(output) = output else { continue };
+        player.is_grounded = output.grounded;
+        if player.is_grounded && player.velocity.y < 0.0 {
+            player.velocity.y = 0.0;
+        }
     }
 }
 
 fn friction(mut player: Query<&mut Player>) {
     let slow_down = 0.8;
-    let mut player = player.get_single_mut().expect("Player exists");
-    if player.is_grounded {
-        player.velocity *= slow_down;
+    for mut player in player.iter_mut() {
+        if player.is_grounded && !player.just_jumped {
+            player.velocity *= slow_down;
+        }
     }
 }
 
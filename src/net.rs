@@ -0,0 +1,106 @@
+//! Rollback netcode subsystem: CLI session setup and the per-frame input encoding shared by
+//! both peers. Kept separate from `main` because nothing here touches gameplay state directly.
+
+use std::net::SocketAddr;
+
+use bevy_ggrs::ggrs::{self, Config, PlayerType, SessionBuilder, UdpNonBlockingSocket};
+use bytemuck::{Pod, Zeroable};
+use clap::Parser;
+
+const INPUT_FORWARD: u16 = 1 << 0;
+const INPUT_BACK: u16 = 1 << 1;
+const INPUT_LEFT: u16 = 1 << 2;
+const INPUT_RIGHT: u16 = 1 << 3;
+const INPUT_JUMP: u16 = 1 << 4;
+
+/// One frame of a player's input, small enough to send every tick and `Pod` so GGRS can hash
+/// and diff it for rollback without a serialization pass. `buttons` is a `u16` (rather than the
+/// `u8` the bitmask needs) purely so the two fields line up with no padding: `derive(Pod)`
+/// refuses to compile over a type with gaps. There's no `pitch_delta`: pitch never affects
+/// simulated movement (see `movement` in `main.rs`), so it stays local-only and isn't networked.
+#[repr(C)]
+#[derive(Copy, Clone, PartialEq, Eq, Default, Pod, Zeroable)]
+pub struct PlayerInput {
+    pub buttons: u16,
+    pub yaw_delta: i16,
+}
+
+impl PlayerInput {
+    pub fn forward(&self) -> bool {
+        self.buttons & INPUT_FORWARD != 0
+    }
+    pub fn back(&self) -> bool {
+        self.buttons & INPUT_BACK != 0
+    }
+    pub fn left(&self) -> bool {
+        self.buttons & INPUT_LEFT != 0
+    }
+    pub fn right(&self) -> bool {
+        self.buttons & INPUT_RIGHT != 0
+    }
+    pub fn jump(&self) -> bool {
+        self.buttons & INPUT_JUMP != 0
+    }
+
+    pub fn pack(forward: bool, back: bool, left: bool, right: bool, jump: bool) -> u16 {
+        let mut buttons = 0;
+        if forward {
+            buttons |= INPUT_FORWARD;
+        }
+        if back {
+            buttons |= INPUT_BACK;
+        }
+        if left {
+            buttons |= INPUT_LEFT;
+        }
+        if right {
+            buttons |= INPUT_RIGHT;
+        }
+        if jump {
+            buttons |= INPUT_JUMP;
+        }
+        buttons
+    }
+}
+
+/// GGRS config tying the input type above to the address type used to reach the other peer.
+pub struct GgrsConfig;
+
+impl Config for GgrsConfig {
+    type Input = PlayerInput;
+    type State = u8;
+    type Address = SocketAddr;
+}
+
+/// Both peers must know each other's address up front since there's no matchmaking server;
+/// `--host` just breaks the handle-0/handle-1 tie so the two sides agree on player order.
+#[derive(Parser, Debug)]
+pub struct NetOpts {
+    /// Take player handle 0 instead of 1.
+    #[arg(long)]
+    pub host: bool,
+
+    /// Local UDP port to bind.
+    #[arg(long, default_value_t = 7000)]
+    pub local_port: u16,
+
+    /// Address of the other peer.
+    #[arg(long)]
+    pub connect: SocketAddr,
+}
+
+pub fn start_p2p_session(opts: &NetOpts) -> ggrs::P2PSession<GgrsConfig> {
+    let (local_handle, remote_handle) = if opts.host { (0, 1) } else { (1, 0) };
+
+    let socket =
+        UdpNonBlockingSocket::bind_to_port(opts.local_port).expect("bind local UDP socket");
+
+    SessionBuilder::<GgrsConfig>::new()
+        .with_num_players(2)
+        .add_player(PlayerType::Local, local_handle)
+        .expect("local player handle")
+        .add_player(PlayerType::Remote(opts.connect), remote_handle)
+        .expect("remote player handle")
+        .start_p2p_session(socket)
+        .expect("start session")
+}